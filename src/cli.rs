@@ -0,0 +1,22 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+/// Remap and hot-plug handler for X11 keyboards.
+#[derive(Parser)]
+#[command(version, about)]
+pub(crate) struct Cli {
+    /// Path to config.toml, overriding the default in the user's config dir.
+    #[arg(long)]
+    pub(crate) config: Option<PathBuf>,
+
+    /// Connect, list detected keyboards (name, device node, xinput id,
+    /// vendor:product) and exit, without running any device rules.
+    #[arg(long)]
+    pub(crate) list: bool,
+
+    /// Run `init_keyboard` for every currently present keyboard whose matching device
+    /// rule sets one and exit, without entering the udev monitor loop.
+    #[arg(long)]
+    pub(crate) once: bool,
+}
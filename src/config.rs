@@ -1,22 +1,141 @@
+use std::collections::HashMap;
 use std::fs;
+use std::path::Path;
 use anyhow::anyhow;
+use evdev::Key;
+use regex::Regex;
 use serde::Deserialize;
 
 #[derive(Deserialize)]
 pub(crate) struct Config {
+    /// Rules matched in order against every keyboard xsprite detects; the first rule
+    /// whose `vendor_product`/`name` patterns both match (when present) is applied.
+    /// Keyboards matching no rule are left alone.
+    #[serde(rename = "device", default)]
+    pub(crate) devices: Vec<DeviceRule>,
+
+    /// Retry schedule used to ride out the window where a hot-plugged keyboard
+    /// hasn't yet fully appeared in the xinput/udev device lists.
+    #[serde(default)]
+    pub(crate) settle: SettleConfig,
+
+    /// Acquire remapped devices through systemd-logind (`TakeDevice` on the user's
+    /// seat) instead of opening `/dev/input/event*` directly, so remapping works
+    /// without running as root. Also enables dropping and re-grabbing devices across
+    /// VT switches via logind's `PauseDevice`/`ResumeDevice` signals.
+    #[serde(default)]
+    pub(crate) use_logind: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DeviceRule {
+    /// Matches against `vendor_id:product_id` formatted as four lowercase hex digits
+    /// each, e.g. `"046d:c52b"`. Either half may be `"*"` to match anything.
+    pub(crate) vendor_product: Option<String>,
+
+    /// Regular expression matched against the device name (an unanchored regex also
+    /// matches as a plain substring, so simple patterns work without escaping).
+    pub(crate) name: Option<String>,
+
     pub(crate) init_keyboard: Option<String>,
+
+    /// In-process key remapping table, as pairs of raw evdev key codes (`from`,
+    /// `to`). When set, xsprite grabs the matched keyboard itself and forwards
+    /// translated events through a virtual keyboard instead of (or in addition to)
+    /// running `init_keyboard`.
+    pub(crate) remap: Option<Vec<(u16, u16)>>,
+}
+
+impl DeviceRule {
+    /// Whether this rule applies to a detected keyboard. A rule with neither
+    /// `vendor_product` nor `name` set matches every keyboard.
+    pub(crate) fn matches(&self, name: &str, vendor_id: u16, product_id: u16) -> bool {
+        if let Some(pattern) = &self.vendor_product {
+            if !vendor_product_matches(pattern, vendor_id, product_id) {
+                return false;
+            }
+        }
+        if let Some(pattern) = &self.name {
+            // Validated at load time, so this regex is known to compile.
+            let re = Regex::new(pattern).expect("name pattern was validated at load time");
+            if !re.is_match(name) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// The parsed `remap` table, keyed and valued by `evdev::Key` rather than raw
+    /// codes, ready to hand to the remap subsystem.
+    pub(crate) fn remap_table(&self) -> Option<HashMap<Key, Key>> {
+        let remap = self.remap.as_ref()?;
+        Some(remap.iter().map(|(from, to)| (Key::new(*from), Key::new(*to))).collect())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub(crate) struct SettleConfig {
+    /// Delay before the first retry, in milliseconds.
+    pub(crate) initial_backoff_ms: u64,
+    /// Upper bound the backoff is allowed to double up to, in milliseconds.
+    pub(crate) max_backoff_ms: u64,
+    /// Total time budget across all retries before giving up, in milliseconds.
+    pub(crate) timeout_ms: u64,
+}
+
+impl Default for SettleConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff_ms: 50,
+            max_backoff_ms: 2000,
+            timeout_ms: 2000,
+        }
+    }
+}
+
+fn vendor_product_matches(pattern: &str, vendor_id: u16, product_id: u16) -> bool {
+    let Some((vendor_pattern, product_pattern)) = pattern.split_once(':') else {
+        return false;
+    };
+    segment_matches(vendor_pattern, vendor_id) && segment_matches(product_pattern, product_id)
+}
+
+fn segment_matches(pattern: &str, actual: u16) -> bool {
+    pattern == "*" || format!("{:04x}", actual).eq_ignore_ascii_case(pattern)
 }
 
-pub(crate) fn load_config() -> anyhow::Result<Config> {
-    // Load config file from user's local .config dir
-    let path = match dirs::config_dir() {
-        Some(path) => path,
+fn validate_vendor_product_pattern(pattern: &str) -> anyhow::Result<()> {
+    let Some((vendor_pattern, product_pattern)) = pattern.split_once(':') else {
+        return Err(anyhow!(
+            "invalid vendor_product pattern {:?}: expected \"vendor:product\"",
+            pattern
+        ));
+    };
+    if !is_valid_segment_pattern(vendor_pattern) || !is_valid_segment_pattern(product_pattern) {
+        return Err(anyhow!(
+            "invalid vendor_product pattern {:?}: each half must be \"*\" or 4 hex digits",
+            pattern
+        ));
+    }
+    Ok(())
+}
+
+fn is_valid_segment_pattern(pattern: &str) -> bool {
+    pattern == "*" || (pattern.len() == 4 && pattern.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+pub(crate) fn load_config(path_override: Option<&Path>) -> anyhow::Result<Config> {
+    let path = match path_override {
+        Some(path) => path.to_path_buf(),
         None => {
-            return Err(anyhow!("failed to find user's config dir"));
+            // Load config file from user's local .config dir
+            let config_dir = dirs::config_dir()
+                .ok_or_else(|| anyhow!("failed to find user's config dir"))?;
+            config_dir.join("xsprite").join("config.toml")
         }
     };
 
-    let path = path.join("xsprite").join("config.toml");
     let contents = fs::read_to_string(&path)
         .map_err(|err| {
             anyhow!("failed to read config file {}: {}", path.display(), err)
@@ -26,12 +145,78 @@ pub(crate) fn load_config() -> anyhow::Result<Config> {
             anyhow!("failed to parse config file {}: {}", path.display(), err)
         })?;
 
-    // Resolve ~ and environment variables in init_keyboard
-    if let Some(init_keyboard) = &mut config.init_keyboard {
-        *init_keyboard = shellexpand::full(init_keyboard)
-            .map_err(|err| {
-                anyhow!("failed to expand init_keyboard: {}", err)
-            })?.into();
+    for device in &mut config.devices {
+        // Resolve ~ and environment variables in init_keyboard
+        if let Some(init_keyboard) = &mut device.init_keyboard {
+            *init_keyboard = shellexpand::full(init_keyboard)
+                .map_err(|err| {
+                    anyhow!("failed to expand init_keyboard: {}", err)
+                })?.into();
+        }
+
+        if let Some(pattern) = &device.name {
+            Regex::new(pattern)
+                .map_err(|err| anyhow!("invalid device name pattern {:?}: {}", pattern, err))?;
+        }
+
+        if let Some(pattern) = &device.vendor_product {
+            validate_vendor_product_pattern(pattern)?;
+        }
     }
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_pattern_accepts_wildcard_and_four_hex_digits() {
+        assert!(is_valid_segment_pattern("*"));
+        assert!(is_valid_segment_pattern("046d"));
+        assert!(is_valid_segment_pattern("FFFF"));
+    }
+
+    #[test]
+    fn segment_pattern_rejects_wrong_length_or_non_hex() {
+        assert!(!is_valid_segment_pattern(""));
+        assert!(!is_valid_segment_pattern("46d"));
+        assert!(!is_valid_segment_pattern("046d1"));
+        assert!(!is_valid_segment_pattern("046g"));
+    }
+
+    #[test]
+    fn segment_matches_is_case_insensitive_and_respects_wildcard() {
+        assert!(segment_matches("*", 0x046d));
+        assert!(segment_matches("046d", 0x046d));
+        assert!(segment_matches("046D", 0x046d));
+        assert!(!segment_matches("046d", 0xc52b));
+    }
+
+    #[test]
+    fn vendor_product_matches_both_halves() {
+        assert!(vendor_product_matches("046d:c52b", 0x046d, 0xc52b));
+        assert!(vendor_product_matches("046d:*", 0x046d, 0xc52b));
+        assert!(vendor_product_matches("*:*", 0x046d, 0xc52b));
+        assert!(!vendor_product_matches("046d:c52b", 0x046d, 0x0000));
+        assert!(!vendor_product_matches("046d:c52b", 0x1234, 0xc52b));
+    }
+
+    #[test]
+    fn vendor_product_matches_rejects_missing_colon() {
+        assert!(!vendor_product_matches("046dc52b", 0x046d, 0xc52b));
+    }
+
+    #[test]
+    fn validate_vendor_product_pattern_accepts_valid_patterns() {
+        assert!(validate_vendor_product_pattern("046d:c52b").is_ok());
+        assert!(validate_vendor_product_pattern("*:*").is_ok());
+    }
+
+    #[test]
+    fn validate_vendor_product_pattern_rejects_malformed_patterns() {
+        assert!(validate_vendor_product_pattern("046dc52b").is_err());
+        assert!(validate_vendor_product_pattern("046d:zzzz").is_err());
+        assert!(validate_vendor_product_pattern("046d:1").is_err());
+    }
+}
@@ -2,17 +2,24 @@ use std::io;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::iter::zip;
+use std::pin::Pin;
 use std::rc::Rc;
+use std::time::Duration;
 
 use breadx::{display::DisplayConnection, prelude::*};
 use breadx::protocol::xinput::{DeviceUse, GetDevicePropertyItems};
 use breadx::protocol::xproto::Atom;
 use futures::Stream;
-use futures::stream::StreamExt;
+use futures::stream::{self, StreamExt};
 use tokio::pin;
 use tokio::process::Command;
+use tokio::time::{sleep, Instant};
 use tokio_udev::{AsyncMonitorSocket, Device, Enumerator, MonitorBuilder};
 
+use crate::config::{Config, DeviceRule, SettleConfig};
+use crate::logind::{self, LogindSession, PauseResumeEvent};
+use crate::remap::{start_remap, RemapHandle, RemapTable};
+
 #[derive(Clone, Debug)]
 struct KeyboardInfo {
     name: String,
@@ -32,6 +39,12 @@ struct UdevKeyboardInfo {
     product_id: u16,
 }
 
+// Keyboards that appeared or disappeared since the last `KeyboardPresenceState::update`.
+struct PresenceChanges {
+    added: Vec<Rc<KeyboardInfo>>,
+    removed: Vec<Rc<KeyboardInfo>>,
+}
+
 struct KeyboardPresenceState<'a> {
     conn: &'a RefCell<DisplayConnection>,
     // Maps from the canonical device note path to the input-device record.
@@ -46,8 +59,7 @@ impl <'a> KeyboardPresenceState<'a> {
         }
     }
 
-    // Returns added keyboards
-    fn update(&mut self) -> Vec<Rc<KeyboardInfo>> {
+    fn update(&mut self) -> PresenceChanges {
         // We get the list of keyboards from xinput, but its classification of devices as
         // keyboards is a little too broad (for example it classifies a power switch as a keyboard).
         // So we also get the list of keyboards from udev, and use that to filter the xinput list
@@ -70,8 +82,8 @@ impl <'a> KeyboardPresenceState<'a> {
         }
 
         // Now we determine which ones were added or removed since the last update.
-        // The added ones will be returned to the caller; the removed ones will
-        // be removed from the known_keyboards map.
+        // Both are returned to the caller; the removed ones are also dropped from
+        // the known_keyboards map.
         let mut added = Vec::with_capacity(keyboards.len());
         for (device_node, keyboard) in keyboards.iter() {
             if !self.known_keyboards.contains_key(device_node) {
@@ -80,9 +92,16 @@ impl <'a> KeyboardPresenceState<'a> {
             }
         }
 
-        self.known_keyboards.retain(|device_node, _| keyboards.contains_key(device_node));
+        let mut removed = Vec::new();
+        self.known_keyboards.retain(|device_node, keyboard| {
+            let still_present = keyboards.contains_key(device_node);
+            if !still_present {
+                removed.push(keyboard.clone());
+            }
+            still_present
+        });
 
-        added
+        PresenceChanges { added, removed }
     }
 
     fn get_udev_keyboards() -> HashMap<String, UdevKeyboardInfo> {
@@ -158,19 +177,221 @@ impl <'a> KeyboardPresenceState<'a> {
     }
 }
 
-pub(crate) async fn reinit_loop(conn: &RefCell<DisplayConnection>, init_keyboard_command: &str) {
+/// Connect once, list whatever keyboards are currently present and print them, one
+/// per line, as `name`, `device_node`, `xinput_id` and `vendor:product` — the exact
+/// values usable in a `[[device]]` rule's `vendor_product`/`name` fields.
+pub(crate) fn print_keyboard_list(conn: &RefCell<DisplayConnection>) {
+    let mut presence_state = KeyboardPresenceState::new(conn);
+    let changes = presence_state.update();
+    for keyboard in changes.added {
+        println!(
+            "{}\t{}\txinput:{}\t{:04x}:{:04x}",
+            keyboard.name, keyboard.device_node, keyboard.xinput_id, keyboard.vendor_id, keyboard.product_id
+        );
+    }
+}
+
+/// Run `init_keyboard` for every currently present keyboard that matches a device
+/// rule, then return without entering the udev monitor loop.
+pub(crate) async fn run_once(conn: &RefCell<DisplayConnection>, config: &Config) {
+    let mut presence_state = KeyboardPresenceState::new(conn);
+    let changes = presence_state.update();
+    for keyboard in changes.added {
+        let Some(rule) = resolve_rule(config, &keyboard) else {
+            continue;
+        };
+        if let Some(init_keyboard_command) = &rule.init_keyboard {
+            init_keyboard(&keyboard, init_keyboard_command).await;
+        }
+    }
+}
+
+pub(crate) async fn reinit_loop(
+    conn: &RefCell<DisplayConnection>,
+    config: &Config,
+    logind: Option<&LogindSession>,
+) {
     let events = monitor_udev_input();
     let events = filter_keyboard_events(events);
     pin!(events);
+
+    let pause_resume: Pin<Box<dyn Stream<Item = PauseResumeEvent> + '_>> = match logind {
+        Some(session) => Box::pin(session.pause_resume_events()),
+        None => Box::pin(stream::pending()),
+    };
+    pin!(pause_resume);
+
     let mut presence_state = KeyboardPresenceState::new(conn);
+    // Remap handles for keyboards we've grabbed ourselves, keyed by device node, torn
+    // down again when the keyboard is removed.
+    let mut remap_handles: HashMap<String, RemapHandle> = HashMap::new();
+    // Keyboards a device rule wants remapped, whether or not currently grabbed
+    // (e.g. paused across a VT switch), keyed by device node.
+    let mut remapped: HashMap<String, Rc<KeyboardInfo>> = HashMap::new();
+    // Whether the next update() should retry-and-settle, because the previous event
+    // we saw was an Add (xinput/udev device properties can lag behind the event).
+    let mut settle_next_update = false;
     loop {
-        let added_keyboards = presence_state.update();
-        for keyboard in added_keyboards {
-            init_keyboard(&keyboard, init_keyboard_command).await;
+        let changes = if settle_next_update {
+            settle_and_update(&mut presence_state, &config.settle).await
+        } else {
+            presence_state.update()
+        };
+        settle_next_update = false;
+
+        for keyboard in changes.removed {
+            remapped.remove(&keyboard.device_node);
+            if let Some(handle) = remap_handles.remove(&keyboard.device_node) {
+                handle.stop().await;
+            }
+        }
+        for keyboard in changes.added {
+            let Some(rule) = resolve_rule(config, &keyboard) else {
+                continue;
+            };
+            if let Some(init_keyboard_command) = &rule.init_keyboard {
+                init_keyboard(&keyboard, init_keyboard_command).await;
+            }
+            if let Some(remap_table) = rule.remap_table() {
+                remapped.insert(keyboard.device_node.clone(), keyboard.clone());
+                start_keyboard_remap(&keyboard, &remap_table, logind, &mut remap_handles).await;
+            }
+        }
+
+        tokio::select! {
+            event = events.next() => match event {
+                Some(event) => {
+                    settle_next_update = event.event_type() == tokio_udev::EventType::Add;
+                }
+                None => {
+                    eprintln!("udev event stream ended, exiting");
+                    return;
+                }
+            },
+            event = pause_resume.next() => {
+                if let Some(event) = event {
+                    handle_pause_resume(event, config, logind, &remapped, &mut remap_handles).await;
+                }
+            }
+        }
+    }
+}
+
+/// React to a `PauseDevice`/`ResumeDevice` signal from logind by dropping or
+/// re-grabbing the matching remapped keyboard, e.g. across a VT switch.
+async fn handle_pause_resume(
+    event: PauseResumeEvent,
+    config: &Config,
+    logind: Option<&LogindSession>,
+    remapped: &HashMap<String, Rc<KeyboardInfo>>,
+    remap_handles: &mut HashMap<String, RemapHandle>,
+) {
+    let Some(logind) = logind else {
+        return;
+    };
+    match event {
+        PauseResumeEvent::Paused { major, minor, reason } => {
+            let Some(device_node) = find_by_device_numbers(remapped, major, minor) else {
+                return;
+            };
+            if let Some(handle) = remap_handles.remove(&device_node) {
+                handle.stop().await;
+            }
+            if reason == "pause" {
+                if let Err(e) = logind.pause_device_complete(major, minor).await {
+                    eprintln!("{}", e);
+                }
+            }
+        }
+        PauseResumeEvent::Resumed { major, minor } => {
+            let Some(device_node) = find_by_device_numbers(remapped, major, minor) else {
+                return;
+            };
+            if remap_handles.contains_key(&device_node) {
+                return;
+            }
+            let keyboard = &remapped[&device_node];
+            let Some(rule) = resolve_rule(config, keyboard) else {
+                return;
+            };
+            let Some(remap_table) = rule.remap_table() else {
+                return;
+            };
+            start_keyboard_remap(keyboard, &remap_table, Some(logind), remap_handles).await;
+        }
+    }
+}
+
+fn find_by_device_numbers(
+    remapped: &HashMap<String, Rc<KeyboardInfo>>,
+    major: u32,
+    minor: u32,
+) -> Option<String> {
+    remapped.keys().find(|device_node| {
+        matches!(logind::device_numbers(device_node), Ok(numbers) if numbers == (major, minor))
+    }).cloned()
+}
+
+/// Call `KeyboardPresenceState::update` repeatedly on an exponential backoff until it
+/// reports an added keyboard or `settle.timeout_ms` elapses, to ride out the window
+/// where the xinput/udev device lists haven't yet caught up with a just-plugged-in
+/// keyboard. `removed` keyboards are accumulated across retries so none are missed.
+async fn settle_and_update(
+    presence_state: &mut KeyboardPresenceState<'_>,
+    settle: &SettleConfig,
+) -> PresenceChanges {
+    let start = Instant::now();
+    let timeout = Duration::from_millis(settle.timeout_ms);
+    let mut backoff_steps = backoff_steps(settle);
+    let mut removed = Vec::new();
+    loop {
+        let mut changes = presence_state.update();
+        removed.append(&mut changes.removed);
+        let elapsed = start.elapsed();
+        if !changes.added.is_empty() || elapsed >= timeout {
+            if changes.added.is_empty() {
+                eprintln!(
+                    "gave up waiting for a newly plugged keyboard to settle after {}ms",
+                    elapsed.as_millis()
+                );
+            }
+            changes.removed = removed;
+            return changes;
         }
-        // Wait for a new keyboard to be plugged in
-        if (events.next().await).is_none() {
-            eprintln!("udev event stream ended, exiting");
+        let backoff = backoff_steps.next().expect("backoff_steps never ends");
+        sleep(backoff.min(timeout - elapsed)).await;
+    }
+}
+
+/// Successive delays `settle_and_update` sleeps between retries: starts at
+/// `initial_backoff_ms`, doubles every step, and never exceeds `max_backoff_ms`.
+fn backoff_steps(settle: &SettleConfig) -> impl Iterator<Item = Duration> + '_ {
+    let max_backoff = Duration::from_millis(settle.max_backoff_ms);
+    let initial = Duration::from_millis(settle.initial_backoff_ms).min(max_backoff);
+    std::iter::successors(Some(initial), move |prev| {
+        Some((*prev * 2).min(max_backoff))
+    })
+}
+
+// The first configured device rule that matches `keyboard`, if any.
+fn resolve_rule<'a>(config: &'a Config, keyboard: &KeyboardInfo) -> Option<&'a DeviceRule> {
+    config.devices.iter().find(|rule| {
+        rule.matches(&keyboard.name, keyboard.vendor_id, keyboard.product_id)
+    })
+}
+
+async fn start_keyboard_remap(
+    keyboard: &KeyboardInfo,
+    remap_table: &RemapTable,
+    logind: Option<&LogindSession>,
+    remap_handles: &mut HashMap<String, RemapHandle>,
+) {
+    match start_remap(&keyboard.device_node, &keyboard.name, remap_table.clone(), logind).await {
+        Ok(handle) => {
+            remap_handles.insert(keyboard.device_node.clone(), handle);
+        }
+        Err(e) => {
+            eprintln!("failed to remap {}: {}", keyboard.device_node, e);
         }
     }
 }
@@ -281,3 +502,42 @@ fn xinput_get_string_property(conn: &mut DisplayConnection, device_id: u8, prope
         _ => panic!("device node is not a string"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settle(initial_backoff_ms: u64, max_backoff_ms: u64, timeout_ms: u64) -> SettleConfig {
+        SettleConfig { initial_backoff_ms, max_backoff_ms, timeout_ms }
+    }
+
+    #[test]
+    fn backoff_steps_starts_at_initial_and_doubles() {
+        let settle = settle(50, 2000, 2000);
+        let steps: Vec<_> = backoff_steps(&settle).take(4).collect();
+        assert_eq!(
+            steps,
+            vec![
+                Duration::from_millis(50),
+                Duration::from_millis(100),
+                Duration::from_millis(200),
+                Duration::from_millis(400),
+            ]
+        );
+    }
+
+    #[test]
+    fn backoff_steps_caps_at_max_backoff() {
+        let settle = settle(50, 120, 2000);
+        let steps: Vec<_> = backoff_steps(&settle).take(4).collect();
+        assert_eq!(
+            steps,
+            vec![
+                Duration::from_millis(50),
+                Duration::from_millis(100),
+                Duration::from_millis(120),
+                Duration::from_millis(120),
+            ]
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+
+use anyhow::Context;
+use futures::stream::{Stream, StreamExt};
+use zbus::zvariant::{OwnedFd, OwnedObjectPath};
+use zbus::{Connection, MessageStream};
+
+const LOGIND_SERVICE: &str = "org.freedesktop.login1";
+const MANAGER_PATH: &str = "/org/freedesktop/login1";
+const MANAGER_INTERFACE: &str = "org.freedesktop.login1.Manager";
+const SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
+
+/// Acquires device file descriptors through systemd-logind instead of opening
+/// `/dev/input/event*` directly, so xsprite can grab a keyboard as an unprivileged
+/// user session rather than needing to run as root. Holds "controlling client"
+/// status on the caller's login session for as long as it's kept around; call
+/// `release_control` on shutdown to give it back up.
+#[derive(Clone)]
+pub(crate) struct LogindSession {
+    conn: Connection,
+    session_path: OwnedObjectPath,
+}
+
+/// A `PauseDevice`/`ResumeDevice` signal from logind, e.g. because the session was
+/// switched away from on a VT switch (`reason == "pause"`, acknowledged
+/// automatically) or the device was revoked outright (`"gone"`/`"force"`).
+#[derive(Debug, Clone)]
+pub(crate) enum PauseResumeEvent {
+    Paused { major: u32, minor: u32, reason: String },
+    Resumed { major: u32, minor: u32 },
+}
+
+impl LogindSession {
+    /// Find the session the current process belongs to and take control of it.
+    pub(crate) async fn connect() -> anyhow::Result<Self> {
+        let conn = Connection::system()
+            .await
+            .context("failed to connect to the system D-Bus")?;
+
+        let reply = conn
+            .call_method(
+                Some(LOGIND_SERVICE),
+                MANAGER_PATH,
+                Some(MANAGER_INTERFACE),
+                "GetSessionByPID",
+                &(std::process::id(),),
+            )
+            .await
+            .context("failed to look up our logind session")?;
+        let session_path: OwnedObjectPath =
+            reply.body().context("malformed GetSessionByPID reply")?;
+
+        conn.call_method(
+            Some(LOGIND_SERVICE),
+            &session_path,
+            Some(SESSION_INTERFACE),
+            "TakeControl",
+            // force=false: don't steal control from another client of the session.
+            &(false,),
+        )
+        .await
+        .context("failed to take control of our logind session")?;
+
+        Ok(Self { conn, session_path })
+    }
+
+    /// Ask logind for an already-open, access-checked file descriptor for
+    /// `device_node` (e.g. `/dev/input/event4`), in place of opening the path
+    /// directly. The returned fd has no corresponding `evdev::Device` constructor;
+    /// see `crate::rawdev` for how it gets read.
+    pub(crate) async fn take_device(&self, device_node: &str) -> anyhow::Result<OwnedFd> {
+        let (major, minor) = device_numbers(device_node)?;
+        let reply = self
+            .conn
+            .call_method(
+                Some(LOGIND_SERVICE),
+                &self.session_path,
+                Some(SESSION_INTERFACE),
+                "TakeDevice",
+                &(major, minor),
+            )
+            .await
+            .with_context(|| format!("failed to take {} via logind", device_node))?;
+        let (fd, _already_paused): (OwnedFd, bool) = reply
+            .body()
+            .with_context(|| format!("malformed TakeDevice reply for {}", device_node))?;
+        Ok(fd)
+    }
+
+    /// Give back a device previously acquired with `take_device`.
+    pub(crate) async fn release_device(&self, device_node: &str) -> anyhow::Result<()> {
+        let (major, minor) = device_numbers(device_node)?;
+        self.conn
+            .call_method(
+                Some(LOGIND_SERVICE),
+                &self.session_path,
+                Some(SESSION_INTERFACE),
+                "ReleaseDevice",
+                &(major, minor),
+            )
+            .await
+            .with_context(|| format!("failed to release {} via logind", device_node))?;
+        Ok(())
+    }
+
+    /// Release control of the session. Called on the SIGINT shutdown path.
+    pub(crate) async fn release_control(&self) {
+        let result = self
+            .conn
+            .call_method(
+                Some(LOGIND_SERVICE),
+                &self.session_path,
+                Some(SESSION_INTERFACE),
+                "ReleaseControl",
+                &(),
+            )
+            .await;
+        if let Err(e) = result {
+            eprintln!("failed to release logind session control: {}", e);
+        }
+    }
+
+    /// Acknowledge a `PauseDevice` signal, letting logind know we're done using the
+    /// device for now. Required before logind will hand it to another session (e.g.
+    /// after a VT switch away); skip this for the "gone"/"force" reasons, which mean
+    /// the device has already been revoked.
+    pub(crate) async fn pause_device_complete(&self, major: u32, minor: u32) -> anyhow::Result<()> {
+        self.conn
+            .call_method(
+                Some(LOGIND_SERVICE),
+                &self.session_path,
+                Some(SESSION_INTERFACE),
+                "PauseDeviceComplete",
+                &(major, minor),
+            )
+            .await
+            .context("failed to ack PauseDevice")?;
+        Ok(())
+    }
+
+    /// Stream of `PauseDevice`/`ResumeDevice` signals for this session.
+    pub(crate) fn pause_resume_events(&self) -> impl Stream<Item = PauseResumeEvent> + '_ {
+        let session_path = self.session_path.clone();
+        MessageStream::from(&self.conn).filter_map(move |message| {
+            let session_path = session_path.clone();
+            async move {
+                let message = message.ok()?;
+                // The direct accessors (as opposed to `message.header()`) don't need to
+                // deserialize the full header, and return it already unwrapped from the
+                // `Result` that only reflects deserialization failure, not absence.
+                if message.path()?.as_str() != session_path.as_str() {
+                    return None;
+                }
+                match message.member()?.as_str() {
+                    "PauseDevice" => {
+                        let (major, minor, reason): (u32, u32, String) = message.body().ok()?;
+                        Some(PauseResumeEvent::Paused { major, minor, reason })
+                    }
+                    "ResumeDevice" => {
+                        let (major, minor, _fd): (u32, u32, OwnedFd) = message.body().ok()?;
+                        Some(PauseResumeEvent::Resumed { major, minor })
+                    }
+                    _ => None,
+                }
+            }
+        })
+    }
+}
+
+/// The (major, minor) device numbers `device_node` refers to, as logind's
+/// `TakeDevice`/`ReleaseDevice` identify devices rather than accepting a path.
+pub(crate) fn device_numbers(device_node: &str) -> anyhow::Result<(u32, u32)> {
+    let meta = fs::metadata(device_node)
+        .with_context(|| format!("failed to stat {}", device_node))?;
+    let rdev = meta.rdev();
+    // Same encoding as glibc's major(3)/minor(3).
+    let major = (((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff)) as u32;
+    let minor = ((rdev & 0xff) | ((rdev >> 12) & !0xff)) as u32;
+    Ok((major, minor))
+}
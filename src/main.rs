@@ -1,18 +1,36 @@
 use std::cell::RefCell;
 use std::pin::Pin;
 use breadx::display::DisplayConnection;
+use clap::Parser;
 use futures::channel::oneshot;
 use futures::prelude::*;
 use signal_hook::consts::signal::SIGINT;
 use signal_hook_tokio::Signals;
+use crate::cli::Cli;
 use crate::init_keyboard::reinit_loop;
+use crate::logind::LogindSession;
 
+mod cli;
 mod config;
 mod init_keyboard;
+mod logind;
+mod rawdev;
+mod remap;
 
 #[tokio::main]
 async fn main() {
-    let config = match config::load_config() {
+    let cli = Cli::parse();
+
+    let conn = RefCell::new(
+        DisplayConnection::connect(None)
+            .expect("failed to connect to X server"));
+
+    if cli.list {
+        init_keyboard::print_keyboard_list(&conn);
+        return;
+    }
+
+    let config = match config::load_config(cli.config.as_deref()) {
         Ok(config) => config,
         Err(err) => {
             eprintln!("Failed to load config: {}", err);
@@ -20,29 +38,47 @@ async fn main() {
         }
     };
 
+    if cli.once {
+        init_keyboard::run_once(&conn, &config).await;
+        return;
+    }
+
+    let logind = if config.use_logind {
+        match LogindSession::connect().await {
+            Ok(session) => Some(session),
+            Err(err) => {
+                eprintln!(
+                    "failed to start logind session, falling back to direct device access: {}",
+                    err
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+
     let signals = Signals::new([SIGINT]).expect("failed to register signal handler");
     let (interrupt_tx, interrupt_rx) = oneshot::channel();
     let signal_handler_task = handle_signals(signals, interrupt_tx);
 
-    let conn = RefCell::new(
-        DisplayConnection::connect(None)
-            .expect("failed to connect to X server"));
-
     let local_set = tokio::task::LocalSet::new();
-    let init_keyboard_task: Pin<Box<dyn Future<Output=()>>> = if let Some(init_keyboard) = &config.init_keyboard {
-        Box::pin(local_set.run_until(reinit_loop(&conn, init_keyboard)))
+    let init_keyboard_task: Pin<Box<dyn Future<Output=()>>> = if !config.devices.is_empty() {
+        Box::pin(local_set.run_until(reinit_loop(&conn, &config, logind.as_ref())))
     } else {
         Box::pin(future::ready(()))
     };
 
     // Wait for SIGINT.
     tokio::select! {
-        _ = interrupt_rx => {
-            return;
-        }
+        _ = interrupt_rx => {}
         _ = init_keyboard_task => {}
         _ = signal_handler_task => {}
     }
+
+    if let Some(session) = &logind {
+        session.release_control().await;
+    }
 }
 
 async fn handle_signals(mut signals: Signals, interrupt_tx: oneshot::Sender<()>) {
@@ -0,0 +1,116 @@
+//! Minimal direct-ioctl access to an already-open evdev file descriptor.
+//!
+//! `evdev::Device` only ever opens devices by path, with no way to wrap an fd we
+//! already hold (e.g. one handed to us by logind's `TakeDevice`). Reopening that same
+//! path ourselves would redo the permission check under our own credentials, which is
+//! exactly what asking logind for the fd was meant to avoid. This module talks to such
+//! an fd directly with the same ioctls `evdev::Device` itself uses internally.
+
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, OwnedFd};
+
+use evdev::{AttributeSet, BusType, EventType, InputEvent, InputId, Key};
+use nix::{ioctl_read, ioctl_read_buf, ioctl_write_int};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+// Linux ioctl magic numbers from <linux/input.h>; mirrors the private ioctls the evdev
+// crate defines for itself in its `sys` module.
+ioctl_write_int!(eviocgrab, b'E', 0x90);
+ioctl_read!(eviocgid, b'E', 0x02, RawInputId);
+ioctl_read_buf!(eviocgbit_key, b'E', 0x20 + EV_KEY, u8);
+
+const EV_KEY: u8 = 0x01;
+// Bytes needed for an EVIOCGBIT(EV_KEY) bitmask covering KEY_MAX (0x2ff) per
+// <linux/input-event-codes.h>.
+const KEY_BITMASK_BYTES: usize = 96;
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct RawInputId {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct RawInputEvent {
+    tv_sec: i64,
+    tv_usec: i64,
+    type_: u16,
+    code: u16,
+    value: i32,
+}
+
+/// A keyboard accessed solely through a raw, already-open file descriptor, bypassing
+/// `evdev::Device::open`/`RawDevice::open` entirely.
+pub(crate) struct RawDevice {
+    fd: OwnedFd,
+}
+
+impl RawDevice {
+    pub(crate) fn new(fd: OwnedFd) -> Self {
+        Self { fd }
+    }
+
+    /// Equivalent to `evdev::Device::grab`.
+    pub(crate) fn grab(&self) -> io::Result<()> {
+        unsafe { eviocgrab(self.fd.as_raw_fd(), 1) }?;
+        Ok(())
+    }
+
+    /// Equivalent to `evdev::Device::input_id`.
+    pub(crate) fn input_id(&self) -> io::Result<InputId> {
+        let mut raw = RawInputId::default();
+        unsafe { eviocgid(self.fd.as_raw_fd(), &mut raw) }?;
+        Ok(InputId::new(BusType(raw.bustype), raw.vendor, raw.product, raw.version))
+    }
+
+    /// Equivalent to `evdev::Device::supported_keys`.
+    pub(crate) fn supported_keys(&self) -> io::Result<AttributeSet<Key>> {
+        let mut bits = [0u8; KEY_BITMASK_BYTES];
+        unsafe { eviocgbit_key(self.fd.as_raw_fd(), &mut bits) }?;
+        let mut keys = AttributeSet::new();
+        for (byte_index, byte) in bits.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (1 << bit) != 0 {
+                    keys.insert(Key::new((byte_index * 8 + bit) as u16));
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Spawn a dedicated OS thread blocking-reading `input_event` records off the fd,
+    /// and return the async-readable channel they're forwarded through. A tokio task
+    /// can't poll this fd directly without the high-level `Device`/`EventStream` types
+    /// this module exists to route around, so the blocking read lives on its own
+    /// thread instead of the runtime.
+    pub(crate) fn into_event_receiver(self) -> UnboundedReceiver<io::Result<InputEvent>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut file = File::from(self.fd);
+        std::thread::spawn(move || {
+            use std::io::Read;
+            loop {
+                let mut raw = RawInputEvent::default();
+                let buf = unsafe {
+                    std::slice::from_raw_parts_mut(
+                        &mut raw as *mut RawInputEvent as *mut u8,
+                        mem::size_of::<RawInputEvent>(),
+                    )
+                };
+                let result = file
+                    .read_exact(buf)
+                    .map(|()| InputEvent::new(EventType(raw.type_), raw.code, raw.value));
+                let stop = result.is_err();
+                if tx.send(result).is_err() || stop {
+                    return;
+                }
+            }
+        });
+        rx
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::io::IntoRawFd;
+
+use anyhow::{anyhow, Context};
+use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+use evdev::{AttributeSet, Device, EventStream, EventType, InputEvent, Key};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::logind::LogindSession;
+use crate::rawdev::RawDevice;
+
+/// Table of key-code substitutions applied to a remapped device. Keys and values are
+/// raw evdev key codes rather than symbolic names, since that's how the config file
+/// expresses them.
+pub(crate) type RemapTable = HashMap<Key, Key>;
+
+/// A running in-process remap of one physical keyboard onto a synthetic uinput
+/// device. Call `stop` to ungrab the physical device and tear down the virtual one.
+/// Dropping the handle also ends forwarding, but does not give a device acquired
+/// through logind back to logind.
+pub(crate) struct RemapHandle {
+    stop_tx: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+    device_node: String,
+    logind: Option<LogindSession>,
+}
+
+impl RemapHandle {
+    pub(crate) async fn stop(mut self) {
+        if let Some(tx) = self.stop_tx.take() {
+            let _ = tx.send(());
+        }
+        let _ = self.task.await;
+        if let Some(logind) = &self.logind {
+            if let Err(e) = logind.release_device(&self.device_node).await {
+                eprintln!("{}", e);
+            }
+        }
+    }
+}
+
+/// The event source a grabbed keyboard is read through, one variant per way
+/// `start_remap` can have obtained the device.
+enum EventSource {
+    /// A keyboard opened directly via `evdev::Device::open`.
+    Evdev(EventStream),
+    /// A keyboard acquired through logind, read via raw ioctls (see `crate::rawdev`)
+    /// since `evdev::Device` has no way to wrap an fd it didn't open itself.
+    Raw(mpsc::UnboundedReceiver<io::Result<InputEvent>>),
+}
+
+impl EventSource {
+    async fn next_event(&mut self) -> io::Result<InputEvent> {
+        match self {
+            EventSource::Evdev(events) => events.next_event().await,
+            EventSource::Raw(events) => events
+                .recv()
+                .await
+                .unwrap_or_else(|| Err(io::Error::new(io::ErrorKind::BrokenPipe, "device closed"))),
+        }
+    }
+}
+
+/// Grab `device_node` exclusively and start forwarding its events to a new virtual
+/// keyboard, translating key codes through `remap_table`. Events with no entry in
+/// `remap_table` (including all non-`EV_KEY` events) are forwarded unchanged.
+///
+/// When `logind` is set, the physical device is obtained through `TakeDevice` rather
+/// than opened directly, so this works without running as root or being in the
+/// `input` group; the virtual uinput device is still created directly, since
+/// `/dev/uinput` access is conventionally granted separately (e.g. a `uinput` group).
+pub(crate) async fn start_remap(
+    device_node: &str,
+    name: &str,
+    remap_table: RemapTable,
+    logind: Option<&LogindSession>,
+) -> anyhow::Result<RemapHandle> {
+    let (input_id, keys, source) = match logind {
+        Some(session) => {
+            let fd = session.take_device(device_node).await?;
+            // Read straight off the fd logind handed us via raw ioctls instead of
+            // `evdev::Device::open`: reopening its /proc/self/fd/N path would perform a
+            // fresh, permission-checked open() under our own credentials, defeating the
+            // point of asking logind for the fd in the first place, and `evdev::Device`
+            // has no public constructor that accepts an already-open fd.
+            let raw_fd = unsafe { OwnedFd::from_raw_fd(fd.into_raw_fd()) };
+            let device = RawDevice::new(raw_fd);
+            device
+                .grab()
+                .with_context(|| format!("failed to grab {}", device_node))?;
+            let input_id = device
+                .input_id()
+                .with_context(|| format!("failed to read input id of {}", device_node))?;
+            let keys = device
+                .supported_keys()
+                .with_context(|| format!("failed to read supported keys of {}", device_node))?;
+            (input_id, keys, EventSource::Raw(device.into_event_receiver()))
+        }
+        None => {
+            let mut device = Device::open(device_node)
+                .with_context(|| format!("failed to open {}", device_node))?;
+            // Exclusive grab, equivalent to an EVIOCGRAB ioctl: once this succeeds,
+            // events no longer reach any other reader (including the X server), so
+            // we're free to hand out translated codes through the virtual device below.
+            device
+                .grab()
+                .with_context(|| format!("failed to grab {}", device_node))?;
+            let input_id = device.input_id();
+            let keys = device
+                .supported_keys()
+                .ok_or_else(|| anyhow!("{} does not report any supported keys", device_node))?
+                .iter()
+                .collect::<AttributeSet<Key>>();
+            let events = device
+                .into_event_stream()
+                .with_context(|| format!("failed to read events from {}", device_node))?;
+            (input_id, keys, EventSource::Evdev(events))
+        }
+    };
+
+    // Copy the physical keyboard's own bus/vendor/product/version so the virtual
+    // device looks like a keyboard rather than a generic, unidentifiable input.
+    let virtual_device = VirtualDeviceBuilder::new()
+        .context("failed to create virtual keyboard")?
+        .name(&format!("xsprite remap: {}", name))
+        .input_id(input_id)
+        .with_keys(&keys)
+        .context("failed to copy supported keys to virtual keyboard")?
+        .build()
+        .context("failed to create virtual keyboard")?;
+
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let task = tokio::spawn(forward_events(source, virtual_device, remap_table, stop_rx));
+
+    Ok(RemapHandle {
+        stop_tx: Some(stop_tx),
+        task,
+        device_node: device_node.to_string(),
+        logind: logind.cloned(),
+    })
+}
+
+async fn forward_events(
+    mut source: EventSource,
+    mut virtual_device: VirtualDevice,
+    remap_table: RemapTable,
+    mut stop_rx: oneshot::Receiver<()>,
+) {
+    loop {
+        let event = tokio::select! {
+            _ = &mut stop_rx => break,
+            event = source.next_event() => event,
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                eprintln!("error reading remapped device event: {}", e);
+                break;
+            }
+        };
+
+        let translated = if event.event_type() == EventType::KEY {
+            let key = Key::new(event.code());
+            let target = remap_table.get(&key).copied().unwrap_or(key);
+            InputEvent::new(event.event_type(), target.code(), event.value())
+        } else {
+            event
+        };
+
+        if let Err(e) = virtual_device.emit(&[translated]) {
+            eprintln!("failed to write remapped event: {}", e);
+            break;
+        }
+    }
+}